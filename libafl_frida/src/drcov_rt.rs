@@ -1,12 +1,16 @@
 //! Generates `DrCov` traces
 use std::{
-    collections::HashMap,
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fs::File,
     hash::{BuildHasher, Hasher},
+    io::Write,
     path::{Path, PathBuf},
     rc::Rc,
 };
 
 use ahash::RandomState;
+use digest::Digest;
 use frida_gum::ModuleMap;
 use libafl::{
     inputs::{HasTargetBytes, Input},
@@ -14,12 +18,323 @@ use libafl::{
 };
 use libafl_bolts::AsSlice;
 use libafl_targets::drcov::{DrCovBasicBlock, DrCovWriter};
+use md5::Md5;
+use object::{Object, ObjectSection, ObjectSymbol, SectionKind, SymbolKind};
 use rangemap::RangeMap;
+use sha1::Sha1;
+use sha2::Sha256;
 
 use crate::helper::FridaRuntime;
 
+/// The algorithm used to hash the input bytes when naming per-input coverage files and, if
+/// module hashing is requested (see [`DrCovRuntime::with_module_hashing`]), module contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    /// The fast, non-cryptographic hash this runtime has always used for filenames.
+    AHash,
+    /// MD5
+    Md5,
+    /// SHA-1
+    Sha1,
+    /// SHA-256
+    Sha256,
+}
+
+impl HashAlgorithm {
+    /// Short, filename-safe tag for this algorithm, written as the `algorithm` field of the
+    /// `modules.json` module-hash sidecar. Module hashes are not written into the `DrCov`
+    /// module table.
+    fn tag(self) -> &'static str {
+        match self {
+            HashAlgorithm::AHash => "ahash",
+            HashAlgorithm::Md5 => "md5",
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+
+    /// Hashes `data`, returning the digest as a lowercase hex string.
+    fn hex_digest(self, data: &[u8]) -> String {
+        match self {
+            HashAlgorithm::AHash => {
+                let mut hasher = RandomState::with_seeds(0, 0, 0, 0).build_hasher();
+                hasher.write(data);
+                format!("{:016x}", hasher.finish())
+            }
+            HashAlgorithm::Md5 => format!("{:x}", Md5::digest(data)),
+            HashAlgorithm::Sha1 => format!("{:x}", Sha1::digest(data)),
+            HashAlgorithm::Sha256 => format!("{:x}", Sha256::digest(data)),
+        }
+    }
+}
+
+/// Reads the module at `path` and hashes its mapped (text/data) sections with `alg`, using the
+/// `object` crate so ELF, Mach-O and PE modules are handled uniformly. Returns `None` if the
+/// module can no longer be read or parsed from disk.
+fn hash_module_file(alg: HashAlgorithm, path: &str) -> Option<String> {
+    let data = std::fs::read(path).ok()?;
+    let object = object::File::parse(&*data).ok()?;
+    let mut mapped = Vec::new();
+    for section in object.sections() {
+        if matches!(
+            section.kind(),
+            SectionKind::Text | SectionKind::Data | SectionKind::ReadOnlyData
+        ) {
+            if let Ok(bytes) = section.data() {
+                mapped.extend_from_slice(bytes);
+            }
+        }
+    }
+    Some(alg.hex_digest(&mapped))
+}
+
+/// Size, in bytes, of a write-ahead-log record's payload: a `u16` module id followed by two
+/// `u64` addresses (start, end).
+const WAL_PAYLOAD_LEN: usize = 2 + 8 + 8;
+
+/// Number of records to buffer between `fsync`s of the write-ahead log. A larger interval means
+/// fewer syscalls per execution, at the cost of a larger window of blocks lost on a crash.
+const WAL_SYNC_INTERVAL: usize = 32;
+
+/// Number of aggregated runs between automatic flushes of the aggregated store to disk, when
+/// `with_aggregation` and `with_write_ahead_log` are combined. Without this, the write-ahead log
+/// would otherwise only be reset in `finalize`/`Drop`, growing for the entire life of the
+/// process and reintroducing the unbounded-disk-growth problem aggregation exists to avoid, just
+/// as one file instead of many.
+const AGGREGATION_FINALIZE_INTERVAL: usize = 4096;
+
+/// Encodes a single write-ahead-log record: a `u32` payload length prefix, followed by the
+/// payload itself. The length prefix lets [`recover_wal_records`] tell a genuine record apart
+/// from a trailing partial one left behind by a crash mid-write.
+fn encode_wal_record(module_id: u16, start: usize, end: usize) -> [u8; 4 + WAL_PAYLOAD_LEN] {
+    let mut record = [0u8; 4 + WAL_PAYLOAD_LEN];
+    record[0..4].copy_from_slice(&(WAL_PAYLOAD_LEN as u32).to_le_bytes());
+    record[4..6].copy_from_slice(&module_id.to_le_bytes());
+    record[6..14].copy_from_slice(&(start as u64).to_le_bytes());
+    record[14..22].copy_from_slice(&(end as u64).to_le_bytes());
+    record
+}
+
+/// Appends `record` to `sink`, looping over short writes instead of assuming a single `write`
+/// call consumes the whole buffer. If a `write` returns `Ok(0)` the target is treated as having
+/// crashed mid-record: the remainder is simply not written, leaving a trailing partial record
+/// that [`recover_wal_records`] will safely ignore on the next startup.
+fn append_wal_record<W: Write>(sink: &mut W, record: &[u8]) -> std::io::Result<()> {
+    let mut written = 0;
+    while written < record.len() {
+        match sink.write(&record[written..])? {
+            0 => break,
+            n => written += n,
+        }
+    }
+    Ok(())
+}
+
+/// Parses as many complete `(module_id, start, end)` records as possible out of a write-ahead
+/// log. Stops at the first incomplete length prefix or payload instead of erroring, so a log
+/// truncated by a crash mid-record yields every block recorded before the crash.
+fn recover_wal_records(log: &[u8]) -> Vec<(u16, usize, usize)> {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while offset + 4 <= log.len() {
+        let len = u32::from_le_bytes(log[offset..offset + 4].try_into().unwrap()) as usize;
+        let payload_start = offset + 4;
+        if len != WAL_PAYLOAD_LEN || payload_start + len > log.len() {
+            break;
+        }
+        let payload = &log[payload_start..payload_start + len];
+        let module_id = u16::from_le_bytes(payload[0..2].try_into().unwrap());
+        let start = u64::from_le_bytes(payload[2..10].try_into().unwrap()) as usize;
+        let end = u64::from_le_bytes(payload[10..18].try_into().unwrap()) as usize;
+        records.push((module_id, start, end));
+        offset = payload_start + len;
+    }
+    records
+}
+
+/// Parses the symbol table (ELF `.symtab`/`.dynsym`, Mach-O `LC_SYMTAB`, PE exports) of every
+/// module in `ranges`, via the `object` crate, and returns a map from absolute address range to
+/// the containing function's name and absolute start address. Modules that can no longer be read
+/// or parsed from disk, or that carry no symbol table, simply contribute no entries.
+///
+/// Stripped PE binaries (the common case for shipped DLLs/EXEs) carry no `.symtab`-equivalent at
+/// all, only an export directory, so `object::Object::exports` is consulted too. Exports have no
+/// size information, so their range is inferred from the next entry's address (or the module's
+/// mapped end, for the last entry) rather than reported directly.
+fn build_symbol_map(ranges: &RangeMap<usize, (u16, String)>) -> RangeMap<usize, (String, usize)> {
+    let mut module_bounds: HashMap<&str, (usize, usize)> = HashMap::new();
+    for (range, (_, path)) in ranges.iter() {
+        module_bounds
+            .entry(path.as_str())
+            .and_modify(|(base, end)| {
+                *base = (*base).min(range.start);
+                *end = (*end).max(range.end);
+            })
+            .or_insert((range.start, range.end));
+    }
+
+    let mut symbol_map = RangeMap::new();
+    let mut symbolized_paths = HashSet::new();
+    for (_, (_, path)) in ranges.iter() {
+        if !symbolized_paths.insert(path.as_str()) {
+            continue;
+        }
+        let Some(&(base, module_end)) = module_bounds.get(path.as_str()) else {
+            continue;
+        };
+        let Ok(data) = std::fs::read(path) else {
+            continue;
+        };
+        let Ok(object) = object::File::parse(&*data) else {
+            continue;
+        };
+
+        // (start, known end, name); `None` end means "infer from the next entry".
+        let mut entries: Vec<(usize, Option<usize>, String)> = Vec::new();
+        for symbol in object.symbols() {
+            if symbol.kind() != SymbolKind::Text || symbol.size() == 0 {
+                continue;
+            }
+            let Ok(name) = symbol.name() else { continue };
+            if name.is_empty() {
+                continue;
+            }
+            let start = base + symbol.address() as usize;
+            entries.push((start, Some(start + symbol.size() as usize), name.to_string()));
+        }
+        for export in object.exports().unwrap_or_default() {
+            let name = String::from_utf8_lossy(export.name()).into_owned();
+            if name.is_empty() {
+                continue;
+            }
+            let start = base + export.address() as usize;
+            entries.push((start, None, name));
+        }
+
+        entries.sort_by_key(|(start, ..)| *start);
+        for i in 0..entries.len() {
+            let (start, known_end, name) = &entries[i];
+            let end = known_end.unwrap_or_else(|| {
+                entries
+                    .get(i + 1)
+                    .map_or(module_end, |(next_start, ..)| *next_start)
+            });
+            if end > *start {
+                symbol_map.insert(*start..end, (name.clone(), *start));
+            }
+        }
+    }
+    symbol_map
+}
+
+/// A basic block interned into the [`AggregatedCoverageStore`], identified by the module it
+/// belongs to and its address range within that module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct InternedBlockKey {
+    module_id: u16,
+    start: usize,
+    end: usize,
+}
+
+/// A single, content-addressed coverage store shared across an entire campaign.
+///
+/// Every distinct basic block is interned exactly once, and each input only stores the sorted
+/// set of block ids it covers, instead of a full copy of every block it hit. This avoids writing
+/// one `.drcov` file (and re-storing every block) for every single execution.
+#[derive(Debug, Default)]
+struct AggregatedCoverageStore {
+    /// All distinct basic blocks ever recorded, in first-seen order; the `Vec` index is the
+    /// block's interned id.
+    blocks: Vec<DrCovBasicBlock>,
+    /// Maps an interned block back to its id in `blocks`.
+    block_ids: HashMap<InternedBlockKey, u32>,
+    /// For every distinct input hash, the sorted set of block ids it covers.
+    references: HashMap<String, Vec<u32>>,
+}
+
+impl AggregatedCoverageStore {
+    /// Interns `block`, returning its (possibly pre-existing) id.
+    fn intern(&mut self, ranges: &RangeMap<usize, (u16, String)>, block: &DrCovBasicBlock) -> u32 {
+        let module_id = ranges.get(&block.start).map_or(0, |(id, _)| *id);
+        let key = InternedBlockKey {
+            module_id,
+            start: block.start,
+            end: block.end,
+        };
+        if let Some(id) = self.block_ids.get(&key) {
+            return *id;
+        }
+        let id = u32::try_from(self.blocks.len()).expect("too many distinct basic blocks");
+        self.blocks.push(block.clone());
+        self.block_ids.insert(key, id);
+        id
+    }
+
+    /// Merges the blocks recorded for a single run into the global table and records the
+    /// resulting reference set under `input_hash`.
+    ///
+    /// If `input_hash` already has a *different* reference set recorded, that is treated as a
+    /// hash collision rather than a re-run of the same input: with the default 64-bit,
+    /// non-cryptographic `AHash`, two distinct inputs colliding is a real risk over the
+    /// large, long-running campaigns this store is meant for. Rather than silently clobbering
+    /// the existing entry, it's kept and the new one is stored under a suffixed key -- the same
+    /// collision-avoidance scheme `post_exec`'s non-aggregated path already uses for filenames.
+    fn record(
+        &mut self,
+        ranges: &RangeMap<usize, (u16, String)>,
+        input_hash: String,
+        blocks: &[DrCovBasicBlock],
+    ) {
+        let mut ids: Vec<u32> = blocks
+            .iter()
+            .map(|block| self.intern(ranges, block))
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+
+        if let Some(existing) = self.references.get(&input_hash) {
+            if *existing == ids {
+                return;
+            }
+            log::warn!(
+                "aggregated coverage: {input_hash} already has a different reference set \
+                 recorded, treating as a hash collision and keeping both"
+            );
+            let mut suffix = 1;
+            let mut suffixed = format!("{input_hash}_{suffix}");
+            while self.references.contains_key(&suffixed) {
+                suffix += 1;
+                suffixed = format!("{input_hash}_{suffix}");
+            }
+            self.references.insert(suffixed, ids);
+            return;
+        }
+        self.references.insert(input_hash, ids);
+    }
+
+    /// Writes the merged `.drcov` and the input-hash -> block-id-set index to `directory`.
+    fn finalize(
+        &self,
+        ranges: &RangeMap<usize, (u16, String)>,
+        directory: &Path,
+    ) -> Result<(), Error> {
+        DrCovWriter::new(ranges).write(directory.join("aggregated.drcov"), &self.blocks)?;
+
+        let mut index = File::create(directory.join("aggregated.index.json"))?;
+        write!(index, "{{")?;
+        for (i, (input_hash, ids)) in self.references.iter().enumerate() {
+            if i > 0 {
+                write!(index, ",")?;
+            }
+            write!(index, "{input_hash:?}:{ids:?}")?;
+        }
+        write!(index, "}}")?;
+        Ok(())
+    }
+}
+
 /// Generates `DrCov` traces
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct DrCovRuntime {
     /// The basic blocks of this execution
     pub drcov_basic_blocks: Vec<DrCovBasicBlock>,
@@ -27,6 +342,39 @@ pub struct DrCovRuntime {
     ranges: RangeMap<usize, (u16, String)>,
     stalked_addresses: HashMap<usize, usize>,
     coverage_directory: PathBuf,
+    /// When set, coverage is merged into a single content-addressed store instead of one
+    /// `.drcov` file per input. See [`DrCovRuntime::with_aggregation`].
+    ///
+    /// Shared behind an `Rc<RefCell<_>>`, rather than deep-cloned, so that `DrCovRuntime` clones
+    /// all accumulate into the *same* store: whichever clone's `Drop` runs last flushes
+    /// everyone's blocks, instead of each clone silently clobbering the others' coverage with its
+    /// own partial view. Being an `Rc`, the store is only shared between clones on the same
+    /// thread; a forked process works on its own copy.
+    aggregation: Option<Rc<RefCell<AggregatedCoverageStore>>>,
+    /// Algorithm used to hash input bytes for filenames, and, if `embed_module_hashes` is set,
+    /// to hash each module's contents for the `modules.json` sidecar.
+    hash_algorithm: HashAlgorithm,
+    /// Whether to write a `modules.json` sidecar with a digest of each module's contents. See
+    /// [`DrCovRuntime::with_module_hashing`].
+    embed_module_hashes: bool,
+    /// Whether to crash-safely log basic blocks to the write-ahead log as they are recorded. See
+    /// [`DrCovRuntime::with_write_ahead_log`].
+    write_ahead_log: bool,
+    /// Records appended to the write-ahead log since its last `fsync`.
+    wal_pending_syncs: usize,
+    /// Aggregated runs recorded since the aggregated store was last flushed to disk. Only used
+    /// when `aggregation` and `write_ahead_log` are both set, to periodically compact the
+    /// write-ahead log; see [`DrCovRuntime::with_write_ahead_log`].
+    aggregated_runs_since_finalize: usize,
+    /// Handle to the write-ahead log, opened once and reused for the life of the runtime instead
+    /// of being reopened for every recorded basic block.
+    wal_file: Option<File>,
+    /// Whether to emit a `<hash>.sym.json` sidecar mapping basic blocks to function names. See
+    /// [`DrCovRuntime::with_symbolization`].
+    symbolize: bool,
+    /// Address range -> (function name, function start address), built once in `init` when
+    /// `symbolize` is set.
+    symbol_map: RangeMap<usize, (String, usize)>,
 }
 
 impl FridaRuntime for DrCovRuntime {
@@ -38,8 +386,24 @@ impl FridaRuntime for DrCovRuntime {
         _module_map: &Rc<ModuleMap>,
     ) {
         self.ranges = ranges.clone();
+        if self.symbolize {
+            self.symbol_map = build_symbol_map(ranges);
+        }
+
         std::fs::create_dir_all(&self.coverage_directory)
             .expect("failed to create directory for coverage files");
+
+        if self.embed_module_hashes {
+            if let Err(err) = self.write_module_hash_sidecar(ranges) {
+                log::error!("failed to write drcov module-hash sidecar: {err}");
+            }
+        }
+
+        if self.write_ahead_log {
+            if let Err(err) = self.recover_write_ahead_log() {
+                log::error!("failed to recover leftover drcov write-ahead log: {err}");
+            }
+        }
     }
 
     /// Called before execution, does nothing
@@ -48,21 +412,53 @@ impl FridaRuntime for DrCovRuntime {
     }
 
     /// Called after execution, writes the trace to a unique `DrCov` file for this trace
-    /// into `./coverage/<trace_hash>.drcov`
+    /// into `./coverage/<trace_hash>.drcov`, or, if [`DrCovRuntime::with_aggregation`] was used,
+    /// merges it into the aggregated, content-addressed coverage store.
     fn post_exec<I: Input + HasTargetBytes>(&mut self, input: &I) -> Result<(), Error> {
-        let mut hasher = RandomState::with_seeds(0, 0, 0, 0).build_hasher();
-        hasher.write(input.target_bytes().as_slice());
+        let hash = self
+            .hash_algorithm
+            .hex_digest(input.target_bytes().as_slice());
+
+        if self.aggregation.is_some() {
+            self.aggregation
+                .as_ref()
+                .expect("just checked is_some")
+                .borrow_mut()
+                .record(&self.ranges, hash, &self.drcov_basic_blocks);
+            self.drcov_basic_blocks.clear();
+
+            if self.write_ahead_log {
+                // The merged store only reaches disk when `finalize` is actually called, so
+                // the write-ahead log must stay intact until then -- otherwise a crash between
+                // this point and the next `finalize` would lose the blocks for good. Left
+                // unchecked that log would grow for the entire campaign, so periodically flush
+                // the aggregated store early (the same bounded-staleness tradeoff
+                // `WAL_SYNC_INTERVAL` already makes for `fsync`) to compact it back down.
+                self.aggregated_runs_since_finalize += 1;
+                if self.aggregated_runs_since_finalize >= AGGREGATION_FINALIZE_INTERVAL {
+                    self.finalize()?;
+                }
+            }
+            return Ok(());
+        }
 
-        let hash = hasher.finish();
-        let mut filename = self.coverage_directory.join(format!("{hash:016x}.drcov"));
+        let mut filename = self.coverage_directory.join(format!("{hash}.drcov"));
         let mut i = 0;
         while filename.exists() {
-            filename.set_file_name(format!("{hash:016x}_{i}.drcov"));
+            filename.set_file_name(format!("{hash}_{i}.drcov"));
             i += 1;
         }
+        if self.symbolize {
+            self.write_symbolized_sidecar(&filename)?;
+        }
         DrCovWriter::new(&self.ranges).write(filename, &self.drcov_basic_blocks)?;
         self.drcov_basic_blocks.clear();
 
+        if self.write_ahead_log {
+            // The blocks are now durable in the `.drcov` above; the log can be reset.
+            self.reset_write_ahead_log()?;
+        }
+
         Ok(())
     }
 }
@@ -82,6 +478,217 @@ impl DrCovRuntime {
         }
     }
 
+    /// Create a new [`DrCovRuntime`] that, instead of writing one `.drcov` file per input,
+    /// interns every distinct basic block once into a single content-addressed store and keeps
+    /// only a compact reference set per input. The merged `.drcov` and its index are written to
+    /// `path` when the runtime is dropped, or via an explicit call to [`DrCovRuntime::finalize`].
+    ///
+    /// The store is shared (not deep-cloned) across any [`Clone`] of this runtime, so clones
+    /// accumulate into one store instead of each clone overwriting the others' coverage when
+    /// dropped. The sharing is through an `Rc`, so it does not extend to other threads or to
+    /// forked processes.
+    pub fn with_aggregation<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            coverage_directory: path.as_ref().into(),
+            aggregation: Some(Rc::new(RefCell::new(AggregatedCoverageStore::default()))),
+            ..Self::default()
+        }
+    }
+
+    /// Compute a digest of each module's contents with `alg`, written out to a `modules.json`
+    /// sidecar so downstream tools can verify a trace was collected against the exact binary
+    /// they are analyzing. Also switches the per-input filename hash from the previously
+    /// hard-coded `ahash` to `alg`, so filenames are reproducible and collision-resistant across
+    /// hosts.
+    #[must_use]
+    pub fn with_module_hashing(mut self, alg: HashAlgorithm) -> Self {
+        self.hash_algorithm = alg;
+        self.embed_module_hashes = true;
+        self
+    }
+
+    /// Writes `modules.json`: one entry per module in `ranges`, giving its path and a digest of
+    /// its contents. Kept as a sidecar, rather than embedded into the `.drcov` module table's
+    /// path field, so the path `DrCovWriter` records stays the real, loadable module path.
+    fn write_module_hash_sidecar(&self, ranges: &RangeMap<usize, (u16, String)>) -> Result<(), Error> {
+        let mut module_paths: HashMap<u16, &str> = HashMap::new();
+        for (_, (module_id, path)) in ranges.iter() {
+            module_paths.entry(*module_id).or_insert(path.as_str());
+        }
+        let mut module_ids: Vec<u16> = module_paths.keys().copied().collect();
+        module_ids.sort_unstable();
+
+        let mut sidecar = File::create(self.coverage_directory.join("modules.json"))?;
+        write!(
+            sidecar,
+            "{{\"algorithm\":{:?},\"modules\":[",
+            self.hash_algorithm.tag()
+        )?;
+        for (i, module_id) in module_ids.iter().enumerate() {
+            let path = module_paths[module_id];
+            let digest = hash_module_file(self.hash_algorithm, path)
+                .unwrap_or_else(|| "unavailable".to_string());
+            if i > 0 {
+                write!(sidecar, ",")?;
+            }
+            write!(
+                sidecar,
+                "{{\"module_id\":{module_id},\"path\":{path:?},\"digest\":{digest:?}}}"
+            )?;
+        }
+        write!(sidecar, "]}}")?;
+        Ok(())
+    }
+
+    /// Flushes the aggregated coverage store to disk, if [`DrCovRuntime::with_aggregation`] was
+    /// used. No-op otherwise. Once the merged store is durable, also resets the write-ahead log
+    /// (if any), since everything it was protecting has now been safely flushed.
+    pub fn finalize(&mut self) -> Result<(), Error> {
+        if let Some(aggregation) = &self.aggregation {
+            aggregation
+                .borrow()
+                .finalize(&self.ranges, &self.coverage_directory)?;
+            self.aggregated_runs_since_finalize = 0;
+            if self.write_ahead_log {
+                self.reset_write_ahead_log()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Crash-safely log every recorded basic block to an append-only write-ahead log before it
+    /// is flushed to a `.drcov` file, so a target crash doesn't lose the coverage for the very
+    /// input that caused it. A leftover log from a previous crashed run is recovered into
+    /// `recovered_<n>.drcov` the next time this runtime is initialized.
+    ///
+    /// Combined with [`DrCovRuntime::with_aggregation`], the log is otherwise only reset when
+    /// the aggregated store is actually flushed to disk, so it is compacted early every
+    /// [`AGGREGATION_FINALIZE_INTERVAL`] aggregated runs instead of growing for the whole
+    /// campaign.
+    #[must_use]
+    pub fn with_write_ahead_log(mut self) -> Self {
+        self.write_ahead_log = true;
+        self
+    }
+
+    /// Path of the write-ahead log.
+    fn wal_path(&self) -> PathBuf {
+        self.coverage_directory.join("wal.log")
+    }
+
+    /// Truncates the write-ahead log and drops the cached handle to it, so the next recorded
+    /// basic block lazily reopens a fresh one.
+    fn reset_write_ahead_log(&mut self) -> Result<(), Error> {
+        self.wal_file = None;
+        std::fs::File::create(self.wal_path())?;
+        self.wal_pending_syncs = 0;
+        Ok(())
+    }
+
+    /// Resolve module symbol tables during [`FridaRuntime::init`] and, alongside every standard
+    /// `.drcov`, emit a `<hash>.sym.json` sidecar mapping each recorded basic block to the name
+    /// of the function containing it and the block's offset from that function's start. No
+    /// module-relative offset is written.
+    #[must_use]
+    pub fn with_symbolization(mut self) -> Self {
+        self.symbolize = true;
+        self
+    }
+
+    /// Writes the `<hash>.sym.json` sidecar for the `.drcov` file at `drcov_path`, mapping every
+    /// basic block recorded for this execution (after resolving stalked addresses) to the
+    /// function that contains it, if any.
+    fn write_symbolized_sidecar(&self, drcov_path: &Path) -> Result<(), Error> {
+        let mut sidecar_path = drcov_path.to_path_buf();
+        sidecar_path.set_extension("sym.json");
+
+        let mut sidecar = File::create(sidecar_path)?;
+        write!(sidecar, "{{\"blocks\":[")?;
+        for (i, block) in self.drcov_basic_blocks.iter().enumerate() {
+            if i > 0 {
+                write!(sidecar, ",")?;
+            }
+            let real_start = self.real_address_for_stalked(block.start);
+            match self.symbol_map.get(&real_start) {
+                Some((name, function_start)) => {
+                    let offset = real_start - function_start;
+                    write!(
+                        sidecar,
+                        "{{\"start\":{},\"end\":{},\"function\":{:?},\"offset\":{offset}}}",
+                        block.start, block.end, name
+                    )?;
+                }
+                None => {
+                    write!(
+                        sidecar,
+                        "{{\"start\":{},\"end\":{},\"function\":null,\"offset\":null}}",
+                        block.start, block.end
+                    )?;
+                }
+            }
+        }
+        write!(sidecar, "]}}")?;
+        Ok(())
+    }
+
+    /// Records a basic block hit during this execution, logging it to the write-ahead log first
+    /// if [`DrCovRuntime::with_write_ahead_log`] was used. Stalker callbacks should call this
+    /// instead of pushing onto [`DrCovRuntime::drcov_basic_blocks`] directly so that crash-safety
+    /// is actually honored.
+    pub fn record_basic_block(&mut self, block: DrCovBasicBlock) -> Result<(), Error> {
+        if self.write_ahead_log {
+            let module_id = self.ranges.get(&block.start).map_or(0, |(id, _)| *id);
+            let record = encode_wal_record(module_id, block.start, block.end);
+
+            if self.wal_file.is_none() {
+                self.wal_file = Some(
+                    std::fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(self.wal_path())?,
+                );
+            }
+            let wal = self.wal_file.as_mut().expect("just opened above");
+            append_wal_record(wal, &record)?;
+
+            self.wal_pending_syncs += 1;
+            if self.wal_pending_syncs >= WAL_SYNC_INTERVAL {
+                wal.sync_data()?;
+                self.wal_pending_syncs = 0;
+            }
+        }
+        self.drcov_basic_blocks.push(block);
+        Ok(())
+    }
+
+    /// If a non-empty write-ahead log was left behind by a previous, crashed run, replays it
+    /// into `recovered_<n>.drcov` and resets the log for the current run.
+    fn recover_write_ahead_log(&mut self) -> Result<(), Error> {
+        let Ok(log) = std::fs::read(self.wal_path()) else {
+            return Ok(());
+        };
+        if log.is_empty() {
+            return Ok(());
+        }
+
+        let recovered_blocks: Vec<DrCovBasicBlock> = recover_wal_records(&log)
+            .into_iter()
+            .map(|(_module_id, start, end)| DrCovBasicBlock { start, end })
+            .collect();
+
+        if !recovered_blocks.is_empty() {
+            let mut filename = self.coverage_directory.join("recovered_0.drcov");
+            let mut i = 0;
+            while filename.exists() {
+                i += 1;
+                filename.set_file_name(format!("recovered_{i}.drcov"));
+            }
+            DrCovWriter::new(&self.ranges).write(filename, &recovered_blocks)?;
+        }
+
+        self.reset_write_ahead_log()
+    }
+
     /// Add a stalked address to real address mapping.
     #[inline]
     pub fn add_stalked_address(&mut self, stalked: usize, real: usize) {
@@ -98,6 +705,39 @@ impl DrCovRuntime {
     }
 }
 
+impl Clone for DrCovRuntime {
+    /// Clones all configuration and accumulated state, except the open write-ahead-log handle
+    /// (`File` isn't `Clone`); the clone lazily reopens it on its first recorded basic block.
+    /// The aggregated coverage store, if any, is shared with the clone rather than copied.
+    fn clone(&self) -> Self {
+        Self {
+            drcov_basic_blocks: self.drcov_basic_blocks.clone(),
+            ranges: self.ranges.clone(),
+            stalked_addresses: self.stalked_addresses.clone(),
+            coverage_directory: self.coverage_directory.clone(),
+            aggregation: self.aggregation.clone(),
+            hash_algorithm: self.hash_algorithm,
+            embed_module_hashes: self.embed_module_hashes,
+            write_ahead_log: self.write_ahead_log,
+            wal_pending_syncs: self.wal_pending_syncs,
+            aggregated_runs_since_finalize: self.aggregated_runs_since_finalize,
+            wal_file: None,
+            symbolize: self.symbolize,
+            symbol_map: self.symbol_map.clone(),
+        }
+    }
+}
+
+impl Drop for DrCovRuntime {
+    fn drop(&mut self) {
+        if self.aggregation.is_some() {
+            if let Err(err) = self.finalize() {
+                log::error!("failed to write aggregated drcov coverage: {err}");
+            }
+        }
+    }
+}
+
 impl Default for DrCovRuntime {
     fn default() -> Self {
         Self {
@@ -105,6 +745,316 @@ impl Default for DrCovRuntime {
             ranges: RangeMap::new(),
             stalked_addresses: HashMap::new(),
             coverage_directory: PathBuf::from("./coverage"),
+            aggregation: None,
+            hash_algorithm: HashAlgorithm::AHash,
+            embed_module_hashes: false,
+            write_ahead_log: false,
+            wal_pending_syncs: 0,
+            aggregated_runs_since_finalize: 0,
+            wal_file: None,
+            symbolize: false,
+            symbol_map: RangeMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        append_wal_record, build_symbol_map, encode_wal_record, hash_module_file,
+        recover_wal_records, AggregatedCoverageStore, DrCovRuntime, HashAlgorithm, WAL_PAYLOAD_LEN,
+    };
+    use crate::drcov_rt::DrCovBasicBlock;
+
+    #[test]
+    fn hex_digest_matches_known_vectors_for_the_empty_input() {
+        assert_eq!(
+            HashAlgorithm::Md5.hex_digest(b""),
+            "d41d8cd98f00b204e9800998ecf8427e"
+        );
+        assert_eq!(
+            HashAlgorithm::Sha1.hex_digest(b""),
+            "da39a3ee5e6b4b0d3255bfef95601890afd80709"
+        );
+        assert_eq!(
+            HashAlgorithm::Sha256.hex_digest(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn hex_digest_is_deterministic_and_sensitive_to_its_input() {
+        let a = HashAlgorithm::AHash.hex_digest(b"aaaa");
+        let b = HashAlgorithm::AHash.hex_digest(b"aaaa");
+        let c = HashAlgorithm::AHash.hex_digest(b"bbbb");
+
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn hash_module_file_returns_none_for_a_path_that_does_not_exist() {
+        assert_eq!(
+            hash_module_file(HashAlgorithm::Sha256, "/nonexistent/path/to/a/module.so"),
+            None
+        );
+    }
+
+    #[test]
+    fn build_symbol_map_skips_modules_that_cannot_be_read() {
+        let mut ranges = rangemap::RangeMap::new();
+        ranges.insert(
+            0x1000..0x2000,
+            (1u16, "/nonexistent/path/to/a/module.so".to_string()),
+        );
+
+        let symbol_map = build_symbol_map(&ranges);
+
+        assert!(symbol_map.get(&0x1010).is_none());
+    }
+
+    #[test]
+    fn write_symbolized_sidecar_reports_symbolized_and_unsymbolized_blocks() {
+        let mut runtime = DrCovRuntime::default();
+        runtime.drcov_basic_blocks = vec![
+            DrCovBasicBlock {
+                start: 0x1010,
+                end: 0x1020,
+            },
+            DrCovBasicBlock {
+                start: 0x5000,
+                end: 0x5010,
+            },
+        ];
+        runtime
+            .symbol_map
+            .insert(0x1000..0x2000, ("some_function".to_string(), 0x1000));
+
+        let drcov_path =
+            std::env::temp_dir().join(format!("drcov_rt_test_{}.drcov", std::process::id()));
+        runtime
+            .write_symbolized_sidecar(&drcov_path)
+            .expect("failed to write symbolized sidecar");
+
+        let mut sidecar_path = drcov_path.clone();
+        sidecar_path.set_extension("sym.json");
+        let contents =
+            std::fs::read_to_string(&sidecar_path).expect("failed to read symbolized sidecar");
+        std::fs::remove_file(&sidecar_path).ok();
+
+        assert_eq!(
+            contents,
+            "{\"blocks\":[{\"start\":4112,\"end\":4128,\"function\":\"some_function\",\"offset\":16},\
+             {\"start\":20480,\"end\":20496,\"function\":null,\"offset\":null}]}"
+        );
+    }
+
+    fn test_ranges() -> rangemap::RangeMap<usize, (u16, String)> {
+        let mut ranges = rangemap::RangeMap::new();
+        ranges.insert(0x1000..0x2000, (1u16, "mod_a".to_string()));
+        ranges.insert(0x2000..0x3000, (2u16, "mod_b".to_string()));
+        ranges
+    }
+
+    #[test]
+    fn intern_collapses_identical_blocks_to_one_id() {
+        let ranges = test_ranges();
+        let mut store = AggregatedCoverageStore::default();
+        let block = DrCovBasicBlock {
+            start: 0x1010,
+            end: 0x1020,
+        };
+
+        let first_id = store.intern(&ranges, &block);
+        let second_id = store.intern(&ranges, &block);
+
+        assert_eq!(first_id, second_id);
+        assert_eq!(store.blocks.len(), 1);
+    }
+
+    #[test]
+    fn intern_keeps_blocks_with_the_same_module_offset_in_different_modules_distinct() {
+        let ranges = test_ranges();
+        let mut store = AggregatedCoverageStore::default();
+        // Same offset-within-module, but module 1 vs module 2 -- must not collide.
+        let in_mod_a = DrCovBasicBlock {
+            start: 0x1010,
+            end: 0x1020,
+        };
+        let in_mod_b = DrCovBasicBlock {
+            start: 0x2010,
+            end: 0x2020,
+        };
+
+        let id_a = store.intern(&ranges, &in_mod_a);
+        let id_b = store.intern(&ranges, &in_mod_b);
+
+        assert_ne!(id_a, id_b);
+        assert_eq!(store.blocks.len(), 2);
+    }
+
+    #[test]
+    fn record_dedups_and_sorts_the_reference_set() {
+        let ranges = test_ranges();
+        let mut store = AggregatedCoverageStore::default();
+
+        store.record(
+            &ranges,
+            "input-a".to_string(),
+            &[
+                DrCovBasicBlock {
+                    start: 0x1030,
+                    end: 0x1040,
+                },
+                DrCovBasicBlock {
+                    start: 0x1010,
+                    end: 0x1020,
+                },
+                DrCovBasicBlock {
+                    start: 0x1010,
+                    end: 0x1020,
+                },
+                DrCovBasicBlock {
+                    start: 0x1030,
+                    end: 0x1040,
+                },
+            ],
+        );
+
+        let ids = &store.references["input-a"];
+        assert_eq!(ids.len(), 2);
+        assert!(
+            ids.windows(2).all(|w| w[0] < w[1]),
+            "ids not sorted: {ids:?}"
+        );
+    }
+
+    #[test]
+    fn record_is_a_no_op_for_an_identical_repeated_input_hash() {
+        let ranges = test_ranges();
+        let mut store = AggregatedCoverageStore::default();
+
+        store.record(
+            &ranges,
+            "same-hash".to_string(),
+            &[DrCovBasicBlock {
+                start: 0x1010,
+                end: 0x1020,
+            }],
+        );
+        store.record(
+            &ranges,
+            "same-hash".to_string(),
+            &[DrCovBasicBlock {
+                start: 0x1010,
+                end: 0x1020,
+            }],
+        );
+
+        assert_eq!(store.references.len(), 1);
+        assert_eq!(store.references["same-hash"].len(), 1);
+    }
+
+    #[test]
+    fn record_keeps_both_reference_sets_on_a_hash_collision() {
+        let ranges = test_ranges();
+        let mut store = AggregatedCoverageStore::default();
+
+        store.record(
+            &ranges,
+            "same-hash".to_string(),
+            &[DrCovBasicBlock {
+                start: 0x1010,
+                end: 0x1020,
+            }],
+        );
+        store.record(
+            &ranges,
+            "same-hash".to_string(),
+            &[DrCovBasicBlock {
+                start: 0x2010,
+                end: 0x2020,
+            }],
+        );
+
+        // A second, different reference set under the same hash must not clobber the first --
+        // it's kept under a suffixed key instead.
+        assert_eq!(store.references.len(), 2);
+        let first_id = store.references["same-hash"][0] as usize;
+        let second_id = store.references["same-hash_1"][0] as usize;
+        assert_eq!(store.blocks[first_id].start, 0x1010);
+        assert_eq!(store.blocks[second_id].start, 0x2010);
+    }
+
+    /// A `Write` sink that accepts only a randomly-sized prefix (at least one byte, at most the
+    /// whole buffer) of every write, so a single record often takes several short writes, the
+    /// way a real file descriptor sometimes does under memory pressure or on a slow filesystem.
+    struct FlakyWriter {
+        buf: Vec<u8>,
+        rng_state: u64,
+    }
+
+    impl FlakyWriter {
+        fn new(seed: u64) -> Self {
+            Self {
+                buf: Vec::new(),
+                rng_state: seed | 1,
+            }
         }
+
+        /// A tiny xorshift PRNG, good enough to vary chunk sizes deterministically per seed.
+        fn next_rand(&mut self) -> u64 {
+            self.rng_state ^= self.rng_state << 13;
+            self.rng_state ^= self.rng_state >> 7;
+            self.rng_state ^= self.rng_state << 17;
+            self.rng_state
+        }
+    }
+
+    impl std::io::Write for FlakyWriter {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            let max_chunk = 1 + (self.next_rand() as usize % data.len().max(1));
+            let n = max_chunk.min(data.len());
+            self.buf.extend_from_slice(&data[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn append_wal_record_survives_short_writes() {
+        for seed in 0..32 {
+            let mut writer = FlakyWriter::new(seed);
+            let record = encode_wal_record(7, 0x1000, 0x1010);
+            append_wal_record(&mut writer, &record).unwrap();
+            assert_eq!(writer.buf, record, "seed {seed} lost bytes to a short write");
+        }
+    }
+
+    #[test]
+    fn recover_wal_records_ignores_trailing_partial_record() {
+        for truncate_at in 0..(4 + WAL_PAYLOAD_LEN) {
+            let mut log = Vec::new();
+            log.extend_from_slice(&encode_wal_record(1, 0x1000, 0x1010));
+            log.extend_from_slice(&encode_wal_record(2, 0x2000, 0x2020));
+            let crashed_record = encode_wal_record(3, 0x3000, 0x3030);
+            log.extend_from_slice(&crashed_record[..truncate_at]);
+
+            let records = recover_wal_records(&log);
+
+            assert_eq!(
+                records,
+                vec![(1, 0x1000, 0x1010), (2, 0x2000, 0x2020)],
+                "truncate_at={truncate_at} produced a corrupt or incomplete recovery"
+            );
+        }
+    }
+
+    #[test]
+    fn recover_wal_records_handles_empty_log() {
+        assert!(recover_wal_records(&[]).is_empty());
     }
 }